@@ -0,0 +1,48 @@
+use std::collections::hash_map::DefaultHasher;
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::path::Path;
+
+use crate::{SemalockConfig, SemalockError};
+
+/// The per-platform lock implementation selected by `Semalock` (see the
+/// `unix`/`windows` modules). Implements the contention-reduction scheme
+/// described on `Semalock::new`: a cheap, racy fast path backed by a named
+/// kernel object, falling back to a blocking, always-correct file lock.
+pub(crate) trait Backend: Sized {
+    /// Allocates (or opens) the named kernel object backing the fast path.
+    fn new(path: &Path, file: &File, config: SemalockConfig) -> Result<Self, SemalockError>;
+
+    /// Acquires the lock for exclusive access, returning whether the slow,
+    /// fast-path-less fallback was taken, in which case `release` must skip
+    /// releasing the fast path since it was never acquired.
+    fn acquire(&self, file: &File) -> Result<bool, SemalockError>;
+
+    /// Acquires the lock for shared (reader) access. The fast path is always
+    /// released by the implementation itself before this returns, so callers
+    /// must always pass `true` to the matching `release`.
+    fn acquire_shared(&self, file: &File) -> Result<(), SemalockError>;
+
+    /// Releases a lock taken by `acquire`/`acquire_shared`.
+    fn release(&self, file: &File, skip_fast_path: bool) -> Result<(), SemalockError>;
+
+    /// Unlinks the named kernel object, so a future `Semalock::new` for the
+    /// same path allocates a fresh one.
+    fn unlink(&self) -> Result<(), SemalockError>;
+
+    /// Whether the most recent `acquire`/`acquire_shared` had to fall back to
+    /// `config.recovery_policy` because the fast path's wait timed out.
+    fn recovered_last_acquire(&self) -> bool;
+}
+
+/// Derives the stable lock name shared by both backends from the path being
+/// locked. This is the same hashing scheme `Semalock` has always used.
+pub(crate) fn lock_name(path: &Path) -> String {
+    let file_hash = {
+        let mut s = DefaultHasher::new();
+        path.to_string_lossy().hash(&mut s);
+        s.finish()
+    };
+
+    format!("fast-lock-{}-{:x}", 0, file_hash)
+}
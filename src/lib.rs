@@ -2,203 +2,276 @@ extern crate errno; /* @TODO really need a dep for this? */
 extern crate libc;
 extern crate tempfile;
 
-use std::collections::hash_map::DefaultHasher;
-use std::error::Error;
-use std::ffi::CString;
-use std::hash::{Hash, Hasher};
+mod backend;
+
+#[cfg(unix)]
+mod unix;
+
+#[cfg(windows)]
+mod windows;
+
+use std::ffi::NulError;
+use std::fmt;
 use std::fs::{ File, OpenOptions };
-use std::os::unix::io::AsRawFd;
+use std::io;
+use std::ops::{ Deref, DerefMut };
 use std::path::Path;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{ Duration, SystemTimeError };
+
+use crate::backend::Backend;
+
+#[cfg(unix)]
+use crate::unix::UnixBackend as PlatformBackend;
+
+#[cfg(windows)]
+use crate::windows::WindowsBackend as PlatformBackend;
+
+/// Errors produced by `Semalock`. Operations that hit a raw POSIX error carry
+/// the `errno::Errno` so callers can match on codes like `libc::EACCES` or
+/// `libc::ENOLCK` instead of parsing a formatted message. Fast-path timeouts
+/// are handled by `RecoveryPolicy` and are never returned as errors.
+#[derive(Debug)]
+pub enum SemalockError {
+    /// Opening (or creating) the backing file failed.
+    Open(io::Error),
+    /// The hashed semaphore name contained an interior NUL byte.
+    SemName(NulError),
+    /// `sem_open` failed.
+    SemOpen(errno::Errno),
+    /// `sem_timedwait` failed for a reason other than `EINTR`/`ETIMEDOUT`.
+    SemWait(errno::Errno),
+    /// `sem_post` failed.
+    SemPost(errno::Errno),
+    /// `sem_getvalue` failed.
+    SemGetValue(errno::Errno),
+    /// `flock` failed.
+    Flock(errno::Errno),
+    /// `sem_unlink` failed.
+    Unlink(errno::Errno),
+    /// The system clock is set before the Unix epoch.
+    Clock(SystemTimeError),
+    /// A platform API call failed; carries the OS error via
+    /// `io::Error::last_os_error()`. Used by non-POSIX backends (currently
+    /// just Windows), whose error codes don't fit the `errno` variants above.
+    Os(io::Error)
+}
+
+impl fmt::Display for SemalockError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SemalockError::Open(e) => write!(f, "OpenOptions::open failed: {}", e),
+            SemalockError::SemName(e) => write!(f, "CString::new failed: {}", e),
+            SemalockError::SemOpen(e) => write!(f, "sem_open {}: {}", e.0, e),
+            SemalockError::SemWait(e) => write!(f, "sem_timedwait {}: {}", e.0, e),
+            SemalockError::SemPost(e) => write!(f, "sem_post {}: {}", e.0, e),
+            SemalockError::SemGetValue(e) => write!(f, "sem_getvalue {}: {}", e.0, e),
+            SemalockError::Flock(e) => write!(f, "flock {}: {}", e.0, e),
+            SemalockError::Unlink(e) => write!(f, "sem_unlink {}: {}", e.0, e),
+            SemalockError::Clock(e) => write!(f, "{}", e),
+            SemalockError::Os(e) => write!(f, "{}", e)
+        }
+    }
+}
+
+impl std::error::Error for SemalockError {}
+
+/// What to do when waiting on the fast path (the named semaphore backing it
+/// on both Unix and Windows) times out. A timeout is taken as a sign that whoever was
+/// holding the lock has crashed, since `SemalockGuard`/`Semalock::release`
+/// otherwise always release it promptly.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RecoveryPolicy {
+    /// Fall back to acquiring the file lock directly, without the fast path.
+    /// This is the crate's original behavior: simple, but a single crashed
+    /// holder permanently pushes every other process for this path onto the
+    /// slower flock-only route, since the semaphore stays decremented.
+    TryFileLock,
+    /// Replace the fast path's kernel object with a fresh one before
+    /// retrying, so a single crashed holder doesn't have a lasting effect on
+    /// other processes' performance. On Windows the semaphore is not
+    /// replaced: this behaves the same as `TryFileLock`.
+    ReinitSemaphore
+}
+
+/// Configuration for a `Semalock`, passed to `Semalock::with_config`.
+#[derive(Clone, Copy, Debug)]
+pub struct SemalockConfig {
+    /// How long to wait on the fast path before falling back to
+    /// `recovery_policy`. Defaults to 10 seconds.
+    pub sem_timeout: Duration,
+    /// What to do when `sem_timeout` elapses. Defaults to `RecoveryPolicy::TryFileLock`.
+    pub recovery_policy: RecoveryPolicy,
+    /// The fast path's initial count: how many processes may be admitted to
+    /// contend for the exclusive file lock at once. Defaults to 1, the
+    /// crate's original binary-lock behavior. Raising this bounds how many
+    /// tasks spin on the OS file lock at any instant, trading a little extra
+    /// `flock` contention for higher throughput under large fan-in. See
+    /// `Semalock::with_permits`.
+    pub permits: u32
+}
 
-pub type SemalockError = String;
+impl Default for SemalockConfig {
+    fn default() -> Self {
+        SemalockConfig {
+            sem_timeout: Duration::from_secs(10),
+            recovery_policy: RecoveryPolicy::TryFileLock,
+            permits: 1
+        }
+    }
+}
 
 pub struct Semalock {
-    fd: i32,
     pub file: File,
-    sem: *mut libc::sem_t,
-    sem_name_cstring: CString
+    backend: PlatformBackend
+}
+
+/// An RAII guard representing a held lock, returned by [`Semalock::lock`].
+/// Derefs to the underlying `File`. The lock is released when the guard is
+/// dropped, even if the drop happens while unwinding from a panic, so callers
+/// no longer need to rely on the 10-second fallback timeout to recover from a
+/// leaked lock. Prefer this over `with` when the critical section doesn't fit
+/// neatly into a single closure.
+pub struct SemalockGuard<'a> {
+    semalock: &'a mut Semalock,
+    skip_sem_post: bool
+}
+
+impl<'a> Deref for SemalockGuard<'a> {
+    type Target = File;
+
+    fn deref(&self) -> &File {
+        &self.semalock.file
+    }
+}
+
+impl<'a> DerefMut for SemalockGuard<'a> {
+    fn deref_mut(&mut self) -> &mut File {
+        &mut self.semalock.file
+    }
+}
+
+impl<'a> Drop for SemalockGuard<'a> {
+    fn drop(&mut self) {
+        // best effort: there's no way to surface an error from a Drop impl,
+        // and this must run exactly once, panicking or not.
+        let _ = self.semalock.backend.release(&self.semalock.file, self.skip_sem_post);
+    }
 }
 
 impl Semalock {
     /// Creates a new `Semalock`, opening or creating the file
-    /// for reading and writing. A POSIX named semaphore is
-    /// allocated (based on the hash of the path) and is used
-    /// to reduce contention when acquiring exclusive file locks.
+    /// for reading and writing. A named semaphore (a POSIX semaphore on Unix,
+    /// a Windows semaphore object on Windows) is allocated based on the hash
+    /// of the path, and is used to reduce contention when acquiring exclusive
+    /// file locks.
     /// On Linux, this is nearly FIFO in terms of acquiring the
     /// lock, though not always. Good (i.e. very minimal CPU usage)
     /// performance has been tested with upto 8192 simultaneous
     /// writers.
+    ///
+    /// Equivalent to `with_config` with the default `SemalockConfig`: a
+    /// 10-second fast-path timeout and `RecoveryPolicy::TryFileLock`.
     pub fn new(path: &Path) -> Result<Semalock, SemalockError> {
+        Semalock::with_config(path, SemalockConfig::default())
+    }
+
+    /// Like `new`, but with an explicit `SemalockConfig` controlling the
+    /// fast-path timeout and the policy used to recover from it.
+    pub fn with_config(path: &Path, config: SemalockConfig) -> Result<Semalock, SemalockError> {
         OpenOptions::new()
             .write(true)
             .read(true)
             .create(true)
             .open(path)
-            .map_err(|e| format!("OpenOptions::open failed: {}", e.description()))
-            .and_then(move |file| {
-                let fd = file.as_raw_fd();
-
-                let file_hash = {
-                    let mut s = DefaultHasher::new();
-                    path.to_string_lossy().hash(&mut s);
-                    s.finish()
-                };
-
-                let sem_name = format!("fast-lock-{}-{:x}", 0, file_hash);
-
-                CString::new(sem_name)
-                    .map_err(|e| format!("CString::new failed: {}", e.description()))
-                    .and_then(move |sem_name_cstring| {
-                        // @TODO move most of this out of unsafe
-                        let sem = unsafe {
-                            let sem = libc::sem_open(sem_name_cstring.as_ptr(), libc::O_CREAT, 0o644, 1);
-
-                            if sem == libc::SEM_FAILED {
-                                let e = errno::errno();
-                                Err(format!("sem_open {}: {}", e.0, e))
-                            } else {
-                                Ok(sem)
-                            }
-                        };
-
-                        sem.map(|sem| Semalock { fd, file, sem, sem_name_cstring })
-                    })
+            .map_err(SemalockError::Open)
+            .and_then(|file| {
+                PlatformBackend::new(path, &file, config).map(|backend| Semalock { file, backend })
             })
     }
 
-    /// Unlinks the semaphore used by this `Semalock` instance. Future
-    /// acquisitions will result in a new kernel object being created.
-    /// This does not affect the data of the file that this lock is
-    /// protecting. See POSIX `sem_unlink` for more details.
+    /// Whether the most recent `acquire`-triggering call (`with`/`lock`/
+    /// `with_shared`/`lock_shared`) had to fall back to `config.recovery_policy`
+    /// because the fast path's wait timed out, presumably due to a crashed
+    /// holder. Useful for operators to monitor how often recovery happens.
+    pub fn recovered_last_acquire(&self) -> bool {
+        self.backend.recovered_last_acquire()
+    }
+
+    /// Like `new`, but lets up to `permits` processes enter and contend for
+    /// the file lock at once, rather than the usual one at a time. This is
+    /// useful as an admission-control throttle for large fan-in writers: a
+    /// small `permits` bounds how many tasks spin on the OS file lock at any
+    /// instant, trading a little extra `flock` contention for higher
+    /// throughput. Equivalent to `with_config` with `permits` set and every
+    /// other field left at its default.
+    pub fn with_permits(path: &Path, permits: u32) -> Result<Semalock, SemalockError> {
+        Semalock::with_config(path, SemalockConfig { permits, ..SemalockConfig::default() })
+    }
+
+    /// Unlinks the named kernel object backing this `Semalock` instance.
+    /// Future acquisitions will result in a new one being created. This does
+    /// not affect the data of the file that this lock is protecting. See
+    /// POSIX `sem_unlink` for more details (on Windows this is a no-op, since
+    /// the underlying kernel object is reference-counted and cleans itself up).
     pub fn unlink(mut self) -> Result<(), SemalockError> {
-        self.with(|s| {
-            let code = unsafe { libc::sem_unlink(s.sem_name_cstring.as_ptr()) };
-
-            if code == 0 {
-                Ok(())
-            } else {
-                let e = errno::errno();
-                Err(format!("sem_unlink {}: {}", e.0, e))
-            }
-        }).and_then(|a| a)
+        self.with(|s| s.backend.unlink()).and_then(|a| a)
     }
 
     /// Acquires the lock, runs the provided function, and releases the lock. If the provided
     /// function panics, the lock is not automatically released. In this case, the secondary
     /// level of exclusive file locks will take effect, temporarily affecting performance
-    /// until a timeout occurs and normal behavior is restored (in other applications).
+    /// until a timeout occurs. Normal behavior (in other applications) is then restored only
+    /// under `RecoveryPolicy::ReinitSemaphore`; under the default `RecoveryPolicy::TryFileLock`
+    /// the fast path stays unavailable and every later acquisition waits out the timeout. If
+    /// panic-safety is needed, or the critical section doesn't fit into a single closure,
+    /// use `lock` instead.
     pub fn with<A, B>(&mut self, a: A) -> Result<B, SemalockError> where A: Fn(&mut Self) -> B {
-        self
-            .acquire()
-            .and_then(|_| {
-                let result = a(self);
+        let skip_sem_post = self.backend.acquire(&self.file)?;
 
-                self
-                    .release()
-                    .map(|_| result)
-            })
-    }
+        let result = a(self);
 
-    fn acquire(&self) -> Result<(), SemalockError> {
-        loop {
-            // algo:
-            //
-            // acquire semaphore with a timeout (say 10 seconds?)
-            // if acquired:
-            //     acquire (blocking) the file lock
-            // if timed out:
-            //     try to acquire exclusive file lock (there is no do! only try!)
-            //     if acquired:
-            //         we're now critical, meaning other process has crashed.
-            //         we can continue as normal
-            //     if failed, repeat acquiring semaphore with timeout
-
-            let sem_timeout_seconds = 10;
-            let now_elapsed_epoch = match SystemTime::now().duration_since(UNIX_EPOCH) {
-                Ok(r) => r,
-                Err(e) => return Err(e.to_string())
-            };
-            let sem_timeout = libc::timespec {
-                tv_sec: (now_elapsed_epoch.as_secs() + sem_timeout_seconds) as i64,
-                tv_nsec: (now_elapsed_epoch.subsec_nanos()) as i64
-            };
-            let call_status = unsafe { libc::sem_timedwait(self.sem, &sem_timeout) };
-
-            if call_status == 0 {
-                let flock_code = unsafe { libc::flock(self.fd, libc::LOCK_EX) };
-
-                if flock_code != 0 {
-                    let e = errno::errno();
-                    return Err(format!("flock {}: {}", e.0, e));
-                }
-
-                return Ok(());
-            } else {
-                let e = errno::errno();
-
-                match e.0 {
-                    libc::EINTR => {},
-
-                    libc::ETIMEDOUT => {
-                        let flock_code = unsafe { libc::flock(self.fd, libc::LOCK_EX) };
-
-                        if flock_code != 0 {
-                            let e = errno::errno();
-
-                            match e.0 {
-                                libc::EINTR => {},
-
-                                libc::EWOULDBLOCK => {},
-
-                                _ => {
-                                    return Err(format!("flock {}: {}", e.0, e))
-                                }
-                            }
-                        }
-
-                        return Ok(());
-                    },
-
-                    _ => {
-                        return Err(format!("sem_timedwait {}: {}", e.0, e))
-                    }
-                }
-            }
-        }
+        self
+            .backend
+            .release(&self.file, skip_sem_post)
+            .map(|_| result)
     }
 
-    fn release(&self) -> Result<(), SemalockError> {
-        let flock_code = unsafe { libc::flock(self.fd, libc::LOCK_UN) };
+    /// Acquires the lock and returns a `SemalockGuard` that releases it on drop,
+    /// including when the drop happens while unwinding from a panic. Unlike
+    /// `with`, the lock can be held across arbitrary control flow rather than a
+    /// single closure.
+    pub fn lock(&mut self) -> Result<SemalockGuard<'_>, SemalockError> {
+        let skip_sem_post = self.backend.acquire(&self.file)?;
 
-        if flock_code != 0 {
-            let e = errno::errno();
-            return Err(format!("flock {}: {}", e.0, e));
-        }
-
-        // @TODO uncomment when new release of libc with my PR is done
-        //let mut value: i32 = 0;
-        //let sem_getvalue_code = unsafe { libc::sem_getvalue(self.sem, &mut value) };
-        let sem_value: i32 = 0;
-        let sem_getvalue_code = 0;
-
-        if sem_getvalue_code != 0 {
-            let e = errno::errno();
-            return Err(format!("sem_getvalue {}: {}", e.0, e));
-        }
-
-        // @TODO sem_value greater than 0, race with other process or bug
-        if sem_value == 0 {
-            let sem_post_code = unsafe { libc::sem_post(self.sem) };
+        Ok(SemalockGuard { semalock: self, skip_sem_post })
+    }
 
-            if sem_post_code != 0 {
-                let e = errno::errno();
+    /// Like `with`, but acquires a shared (reader) file lock rather than an
+    /// exclusive one, allowing many processes to run `a` concurrently. A
+    /// concurrent call to `with`/`lock` still serializes against readers at
+    /// the OS level via the exclusive file lock.
+    ///
+    /// Unlike the exclusive path, the fast path's kernel object is released
+    /// immediately after the shared file lock is taken rather than held for
+    /// the duration of the critical section, so a writer waiting on it isn't
+    /// starved by a long-running or continuous stream of readers.
+    pub fn with_shared<A, B>(&mut self, a: A) -> Result<B, SemalockError> where A: Fn(&mut Self) -> B {
+        self.backend.acquire_shared(&self.file)?;
+
+        let result = a(self);
+
+        // the fast path was already released by `acquire_shared`
+        self
+            .backend
+            .release(&self.file, true)
+            .map(|_| result)
+    }
 
-                return Err(format!("sem_post {}: {}", e.0, e));
-            }
-        }
+    /// Like `lock`, but acquires a shared (reader) file lock. See `with_shared`.
+    pub fn lock_shared(&mut self) -> Result<SemalockGuard<'_>, SemalockError> {
+        self.backend.acquire_shared(&self.file)?;
 
-        Ok(())
+        Ok(SemalockGuard { semalock: self, skip_sem_post: true })
     }
 }
 
@@ -221,6 +294,31 @@ fn basic_usage() {
     remove_file(path).unwrap();
 }
 
+#[test]
+fn lock_releases_on_panic() {
+    use std::panic;
+    use tempfile::NamedTempFile;
+
+    let file = NamedTempFile::new().unwrap();
+    let path = file.path();
+
+    {
+        let mut lock = Semalock::new(path).unwrap();
+
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            let _guard = lock.lock().unwrap();
+            panic!("boom");
+        }));
+
+        assert!(result.is_err());
+    }
+
+    // the guard's Drop ran during the unwind, so a fresh acquisition
+    // should not have to wait on the fallback timeout
+    let mut lock = Semalock::new(path).unwrap();
+    lock.lock().unwrap();
+}
+
 // @TODO concurrency_processes (should just work)
 
 #[test]
@@ -281,6 +379,195 @@ fn concurrency_threads() {
     assert_eq!(sum, expected);
 }
 
+#[test]
+fn concurrency_with_permits() {
+    use std::fs;
+    use std::io::prelude::*;
+    use std::io::{ SeekFrom, Write };
+
+    let path_str = {
+        // immediately goes out of scope and gets deleted,
+        // then we manage it ourselves
+        let path_str = tempfile::NamedTempFile::new()
+            .unwrap()
+            .path()
+            .to_str()
+            .unwrap()
+            .to_string();
+
+        assert!(!Path::new(&path_str).exists());
+
+        path_str
+    };
+
+    let num_threads = 512;
+
+    let threads: Vec<std::thread::JoinHandle<()>> =
+        (0..num_threads).map(|n| {
+            let n = n.clone();
+            let path_str = path_str.clone();
+            std::thread::spawn(move || {
+                let mut lock = Semalock::with_permits(Path::new(&path_str), 8).unwrap();
+                lock.with(|lock| {
+                    lock.file.seek(SeekFrom::End(0)).unwrap();
+                    lock.file.write_all(format!("{}\n", n).as_bytes()).unwrap();
+                }).unwrap();
+            })
+        }).collect();
+
+    for t in threads {
+        t.join().unwrap();
+    }
+
+    let path = Path::new(&path_str);
+    let mut result = String::new();
+    let mut file = File::open(&path).unwrap();
+    file.read_to_string(&mut result).unwrap();
+    let lock = Semalock::new(Path::new(&path)).unwrap();
+    lock.unlink().unwrap();
+    fs::remove_file(Path::new(&path)).unwrap();
+
+    let sum = result
+        .lines()
+        .map(|l| l.parse::<i32>().unwrap())
+        .sum::<i32>();
+
+    let expected = num_threads * (num_threads - 1) / 2;
+
+    // raising the permit count lets more threads contend for the file lock
+    // at once, but every write is still serialized through it, so the sum
+    // (and therefore, that no writes were lost or corrupted) is unaffected
+    assert_eq!(sum, expected);
+}
+
+// @TODO concurrency_processes_shared (should just work)
+
+#[test]
+fn concurrency_shared_threads() {
+    use std::fs;
+    use std::io::prelude::*;
+    use std::io::{ SeekFrom, Write };
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    let path_str = {
+        // immediately goes out of scope and gets deleted,
+        // then we manage it ourselves
+        let path_str = tempfile::NamedTempFile::new()
+            .unwrap()
+            .path()
+            .to_str()
+            .unwrap()
+            .to_string();
+
+        assert!(!Path::new(&path_str).exists());
+
+        path_str
+    };
+
+    let num_readers = 256;
+    let reads_seen = Arc::new(AtomicUsize::new(0));
+
+    let mut lock = Semalock::new(Path::new(&path_str)).unwrap();
+    lock.with(|lock| {
+        lock.file.write_all(b"hello world!").unwrap();
+    }).unwrap();
+
+    let mut threads: Vec<std::thread::JoinHandle<()>> = Vec::new();
+
+    // many concurrent readers
+    for _ in 0..num_readers {
+        let path_str = path_str.clone();
+        let reads_seen = reads_seen.clone();
+
+        threads.push(std::thread::spawn(move || {
+            let mut lock = Semalock::new(Path::new(&path_str)).unwrap();
+            lock.with_shared(|lock| {
+                let mut contents = String::new();
+                lock.file.seek(SeekFrom::Start(0)).unwrap();
+                lock.file.read_to_string(&mut contents).unwrap();
+                assert!(contents.starts_with("hello world!"));
+            }).unwrap();
+            reads_seen.fetch_add(1, Ordering::SeqCst);
+        }));
+    }
+
+    // one writer, contending with the readers via the fallback file lock
+    {
+        let path_str = path_str.clone();
+        threads.push(std::thread::spawn(move || {
+            let mut lock = Semalock::new(Path::new(&path_str)).unwrap();
+            lock.with(|lock| {
+                lock.file.seek(SeekFrom::End(0)).unwrap();
+                lock.file.write_all(b" writer").unwrap();
+            }).unwrap();
+        }));
+    }
+
+    for t in threads {
+        t.join().unwrap();
+    }
+
+    assert_eq!(reads_seen.load(Ordering::SeqCst), num_readers);
+
+    let path = Path::new(&path_str);
+    let mut result = String::new();
+    let mut file = File::open(&path).unwrap();
+    file.read_to_string(&mut result).unwrap();
+    let lock = Semalock::new(Path::new(&path)).unwrap();
+    lock.unlink().unwrap();
+    fs::remove_file(Path::new(&path)).unwrap();
+
+    assert_eq!(result, "hello world! writer");
+}
+
+#[test]
+fn with_config_reinit_semaphore_recovers() {
+    use std::sync::mpsc;
+    use tempfile::NamedTempFile;
+
+    let file = NamedTempFile::new().unwrap();
+    let path_str = file.path().to_str().unwrap().to_string();
+
+    // hold the lock on a separate thread for longer than the short timeout
+    // below, so the fast path is guaranteed to time out, forcing the main
+    // thread through `ReinitSemaphore` rather than just finding the
+    // semaphore free
+    let (holder_ready_tx, holder_ready_rx) = mpsc::channel();
+
+    let holder = {
+        let path_str = path_str.clone();
+
+        std::thread::spawn(move || {
+            let mut lock = Semalock::new(Path::new(&path_str)).unwrap();
+            let _guard = lock.lock().unwrap();
+            holder_ready_tx.send(()).unwrap();
+            std::thread::sleep(Duration::from_millis(150));
+        })
+    };
+
+    holder_ready_rx.recv().unwrap();
+
+    let config = SemalockConfig {
+        sem_timeout: Duration::from_millis(50),
+        recovery_policy: RecoveryPolicy::ReinitSemaphore,
+        ..SemalockConfig::default()
+    };
+
+    let mut lock = Semalock::with_config(Path::new(&path_str), config).unwrap();
+
+    assert!(!lock.recovered_last_acquire());
+
+    // the fast path is held by the other thread, so this times out and
+    // falls back to `ReinitSemaphore`, blocking on the file lock until the
+    // holder thread's guard drops
+    lock.with(|_| {}).unwrap();
+
+    assert!(lock.recovered_last_acquire());
+
+    holder.join().unwrap();
+}
+
 #[test]
 fn unlink_and_use_again() {
     use std::fs::remove_file;
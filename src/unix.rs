@@ -0,0 +1,284 @@
+extern crate errno;
+extern crate libc;
+
+use std::cell::Cell;
+use std::ffi::CString;
+use std::fs::File;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::backend::{lock_name, Backend};
+use crate::{RecoveryPolicy, SemalockConfig, SemalockError};
+
+/// The POSIX backend: a named semaphore (the fast path) guarding a blocking
+/// `flock` (the always-correct fallback). See `Semalock::new`.
+pub(crate) struct UnixBackend {
+    sem: Cell<*mut libc::sem_t>,
+    sem_name_cstring: CString,
+    config: SemalockConfig,
+    recovered: Cell<bool>
+}
+
+// `sem` is a pointer into POSIX's named semaphore namespace, not process
+// memory; it's safe to share across threads the same way a raw fd is.
+unsafe impl Send for UnixBackend {}
+unsafe impl Sync for UnixBackend {}
+
+impl UnixBackend {
+    fn open_sem(sem_name_cstring: &CString, permits: u32) -> Result<*mut libc::sem_t, SemalockError> {
+        let sem = unsafe { libc::sem_open(sem_name_cstring.as_ptr(), libc::O_CREAT, 0o644, permits) };
+
+        if sem == libc::SEM_FAILED {
+            let e = errno::errno();
+            Err(SemalockError::SemOpen(e))
+        } else {
+            Ok(sem)
+        }
+    }
+
+    /// Unlinks the (presumed stale, permanently-decremented) named semaphore
+    /// and opens a fresh one, reset to `config.permits`, in its place, so
+    /// future acquisitions can use the fast path again instead of this
+    /// crashed holder permanently degrading every other process to the
+    /// flock-only fallback.
+    fn reinit_semaphore(&self) -> Result<(), SemalockError> {
+        // best effort: if this races with another process doing the same
+        // thing, whichever `sem_open` runs last wins, and either outcome is
+        // a valid fresh semaphore
+        unsafe { libc::sem_unlink(self.sem_name_cstring.as_ptr()); }
+
+        let sem = UnixBackend::open_sem(&self.sem_name_cstring, self.config.permits)?;
+
+        // close the old descriptor so repeated trips through this path (the
+        // crashy-neighbor scenario `ReinitSemaphore` targets) don't leak one
+        // per recovery
+        unsafe { libc::sem_close(self.sem.get()); }
+
+        self.sem.set(sem);
+
+        Ok(())
+    }
+
+    fn sem_timeout(&self) -> Result<libc::timespec, SemalockError> {
+        let deadline = match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(now) => now + self.config.sem_timeout,
+            Err(e) => return Err(SemalockError::Clock(e))
+        };
+
+        Ok(libc::timespec {
+            tv_sec: deadline.as_secs() as i64,
+            tv_nsec: deadline.subsec_nanos() as i64
+        })
+    }
+}
+
+impl Backend for UnixBackend {
+    fn new(path: &Path, _file: &File, config: SemalockConfig) -> Result<Self, SemalockError> {
+        let sem_name_cstring = CString::new(lock_name(path)).map_err(SemalockError::SemName)?;
+
+        // @TODO move most of this out of unsafe
+        let sem = UnixBackend::open_sem(&sem_name_cstring, config.permits)?;
+
+        Ok(UnixBackend { sem: Cell::new(sem), sem_name_cstring, config, recovered: Cell::new(false) })
+    }
+
+    fn acquire(&self, file: &File) -> Result<bool, SemalockError> {
+        self.recovered.set(false);
+
+        loop {
+            // algo:
+            //
+            // acquire semaphore with a timeout
+            // if acquired:
+            //     acquire (blocking) the file lock
+            // if timed out:
+            //     apply config.recovery_policy:
+            //         TryFileLock: try to acquire the exclusive file lock directly,
+            //             assuming the previous holder crashed
+            //         ReinitSemaphore: replace the (presumed stuck) semaphore with a
+            //             fresh one and retry
+
+            let sem_timeout = self.sem_timeout()?;
+            let call_status = unsafe { libc::sem_timedwait(self.sem.get(), &sem_timeout) };
+
+            if call_status == 0 {
+                let flock_code = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) };
+
+                if flock_code != 0 {
+                    let e = errno::errno();
+                    return Err(SemalockError::Flock(e));
+                }
+
+                return Ok(false);
+            } else {
+                let e = errno::errno();
+
+                match e.0 {
+                    libc::EINTR => {},
+
+                    libc::ETIMEDOUT => {
+                        match self.config.recovery_policy {
+                            RecoveryPolicy::TryFileLock => {
+                                let flock_code = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) };
+
+                                if flock_code != 0 {
+                                    let e = errno::errno();
+
+                                    match e.0 {
+                                        libc::EINTR => {},
+
+                                        libc::EWOULDBLOCK => {},
+
+                                        _ => {
+                                            return Err(SemalockError::Flock(e))
+                                        }
+                                    }
+                                }
+
+                                self.recovered.set(true);
+
+                                return Ok(true);
+                            },
+
+                            RecoveryPolicy::ReinitSemaphore => {
+                                self.reinit_semaphore()?;
+                                self.recovered.set(true);
+                                // retry, racing the fresh semaphore against other processes
+                            }
+                        }
+                    },
+
+                    _ => {
+                        return Err(SemalockError::SemWait(e))
+                    }
+                }
+            }
+        }
+    }
+
+    fn acquire_shared(&self, file: &File) -> Result<(), SemalockError> {
+        self.recovered.set(false);
+
+        loop {
+            let sem_timeout = self.sem_timeout()?;
+            let call_status = unsafe { libc::sem_timedwait(self.sem.get(), &sem_timeout) };
+
+            if call_status == 0 {
+                let flock_code = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_SH) };
+
+                if flock_code != 0 {
+                    let e = errno::errno();
+                    unsafe { libc::sem_post(self.sem.get()); }
+                    return Err(SemalockError::Flock(e));
+                }
+
+                // release the semaphore immediately: the shared flock is what
+                // protects the critical section, so other readers (and a
+                // writer waiting in `acquire`) shouldn't have to wait on us.
+                let sem_post_code = unsafe { libc::sem_post(self.sem.get()) };
+
+                if sem_post_code != 0 {
+                    let e = errno::errno();
+                    return Err(SemalockError::SemPost(e));
+                }
+
+                return Ok(());
+            } else {
+                let e = errno::errno();
+
+                match e.0 {
+                    libc::EINTR => {},
+
+                    libc::ETIMEDOUT => {
+                        match self.config.recovery_policy {
+                            RecoveryPolicy::TryFileLock => {
+                                let flock_code = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_SH) };
+
+                                if flock_code != 0 {
+                                    let e = errno::errno();
+
+                                    match e.0 {
+                                        libc::EINTR => {},
+
+                                        libc::EWOULDBLOCK => {},
+
+                                        _ => {
+                                            return Err(SemalockError::Flock(e))
+                                        }
+                                    }
+                                }
+
+                                self.recovered.set(true);
+
+                                return Ok(());
+                            },
+
+                            RecoveryPolicy::ReinitSemaphore => {
+                                self.reinit_semaphore()?;
+                                self.recovered.set(true);
+                                // retry, racing the fresh semaphore against other processes
+                            }
+                        }
+                    },
+
+                    _ => {
+                        return Err(SemalockError::SemWait(e))
+                    }
+                }
+            }
+        }
+    }
+
+    fn release(&self, file: &File, skip_fast_path: bool) -> Result<(), SemalockError> {
+        let flock_code = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_UN) };
+
+        if flock_code != 0 {
+            let e = errno::errno();
+            return Err(SemalockError::Flock(e));
+        }
+
+        if skip_fast_path {
+            return Ok(());
+        }
+
+        // @TODO uncomment when new release of libc with my PR is done
+        //let mut value: i32 = 0;
+        //let sem_getvalue_code = unsafe { libc::sem_getvalue(self.sem.get(), &mut value) };
+        let sem_value: i32 = 0;
+        let sem_getvalue_code = 0;
+
+        if sem_getvalue_code != 0 {
+            let e = errno::errno();
+            return Err(SemalockError::SemGetValue(e));
+        }
+
+        // @TODO sem_value greater than 0, race with other process or bug
+        if sem_value == 0 {
+            let sem_post_code = unsafe { libc::sem_post(self.sem.get()) };
+
+            if sem_post_code != 0 {
+                let e = errno::errno();
+
+                return Err(SemalockError::SemPost(e));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn unlink(&self) -> Result<(), SemalockError> {
+        let code = unsafe { libc::sem_unlink(self.sem_name_cstring.as_ptr()) };
+
+        if code == 0 {
+            Ok(())
+        } else {
+            let e = errno::errno();
+            Err(SemalockError::Unlink(e))
+        }
+    }
+
+    fn recovered_last_acquire(&self) -> bool {
+        self.recovered.get()
+    }
+}
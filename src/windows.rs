@@ -0,0 +1,191 @@
+extern crate winapi;
+
+use std::cell::Cell;
+use std::cmp;
+use std::fs::File;
+use std::io;
+use std::iter::once;
+use std::os::windows::ffi::OsStrExt;
+use std::os::windows::io::AsRawHandle;
+use std::path::Path;
+use std::ptr;
+
+use winapi::shared::minwindef::{DWORD, FALSE, MAX_PATH};
+use winapi::shared::winerror::WAIT_TIMEOUT;
+use winapi::um::fileapi::{LockFileEx, UnlockFile};
+use winapi::um::handleapi::{CloseHandle, INVALID_HANDLE_VALUE};
+use winapi::um::minwinbase::{LOCKFILE_EXCLUSIVE_LOCK, OVERLAPPED};
+use winapi::um::synchapi::{CreateSemaphoreW, ReleaseSemaphore, WaitForSingleObject};
+use winapi::um::winbase::WAIT_OBJECT_0;
+use winapi::um::winnt::HANDLE;
+
+use crate::backend::{lock_name, Backend};
+use crate::{SemalockConfig, SemalockError};
+
+/// The Windows backend: a named semaphore (the fast path, mirroring the
+/// POSIX semaphore's contention-reducing role, including its counting
+/// `permits` behavior) guarding a blocking `LockFileEx` (the always-correct
+/// fallback). See `Semalock::new`.
+pub(crate) struct WindowsBackend {
+    sem: HANDLE,
+    config: SemalockConfig,
+    recovered: Cell<bool>
+}
+
+// `sem` is a kernel handle, safe to use from any thread that holds the
+// `WindowsBackend`, the same way the Unix backend shares a raw fd.
+unsafe impl Send for WindowsBackend {}
+unsafe impl Sync for WindowsBackend {}
+
+fn sem_name(path: &Path) -> Vec<u16> {
+    // session-local ("Local\") kernel namespace, same scope POSIX named
+    // semaphores get on this platform
+    let name: Vec<u16> = format!("Local\\{}", lock_name(path))
+        .encode_utf16()
+        .chain(once(0))
+        .collect();
+
+    // CreateSemaphoreW silently truncates names over MAX_PATH; the hashed
+    // name is always well under that, but guard against surprises from a
+    // future naming scheme change
+    debug_assert!(name.len() < MAX_PATH as usize);
+
+    name
+}
+
+fn overlapped() -> OVERLAPPED {
+    unsafe { std::mem::zeroed() }
+}
+
+fn sem_timeout_ms(config: &SemalockConfig) -> DWORD {
+    cmp::min(config.sem_timeout.as_millis(), DWORD::max_value() as u128) as DWORD
+}
+
+impl Backend for WindowsBackend {
+    fn new(path: &Path, _file: &File, config: SemalockConfig) -> Result<Self, SemalockError> {
+        let name = sem_name(path);
+        let permits = config.permits as i32;
+
+        let sem = unsafe { CreateSemaphoreW(ptr::null_mut(), permits, permits, name.as_ptr()) };
+
+        if sem.is_null() {
+            return Err(SemalockError::Os(io::Error::last_os_error()));
+        }
+
+        Ok(WindowsBackend { sem, config, recovered: Cell::new(false) })
+    }
+
+    fn acquire(&self, file: &File) -> Result<bool, SemalockError> {
+        // same algo as the Unix backend's `acquire`: wait on the fast path
+        // with a timeout, then fall back to taking the file lock directly on
+        // the assumption that whoever held it has crashed. Unlike a mutex, a Windows
+        // semaphore doesn't report abandonment, so (as on Unix) a crashed
+        // holder just looks like a timeout here.
+        self.recovered.set(false);
+
+        let wait_status = unsafe { WaitForSingleObject(self.sem, sem_timeout_ms(&self.config)) };
+
+        if wait_status == WAIT_OBJECT_0 {
+            lock_file(file, LOCKFILE_EXCLUSIVE_LOCK)?;
+
+            Ok(false)
+        } else if wait_status == WAIT_TIMEOUT {
+            // both `RecoveryPolicy` variants reduce to the same fallback
+            // here: replacing the semaphore (as `ReinitSemaphore` does on
+            // Unix) would require a second named object to coordinate the
+            // swap race-free, which isn't worth it when the blocking
+            // fallback already recovers correctly. This blocks rather than
+            // try-locking, matching the Unix backend's `TryFileLock` path:
+            // a timeout doesn't necessarily mean the holder crashed, it may
+            // just be live contention, so we still wait for the file lock
+            // rather than surfacing a spurious error.
+            lock_file(file, LOCKFILE_EXCLUSIVE_LOCK)?;
+
+            self.recovered.set(true);
+
+            Ok(true)
+        } else {
+            Err(SemalockError::Os(io::Error::last_os_error()))
+        }
+    }
+
+    fn acquire_shared(&self, file: &File) -> Result<(), SemalockError> {
+        self.recovered.set(false);
+
+        let wait_status = unsafe { WaitForSingleObject(self.sem, sem_timeout_ms(&self.config)) };
+
+        if wait_status == WAIT_OBJECT_0 {
+            let result = lock_file(file, 0);
+
+            // release the fast path immediately, same reasoning as the Unix
+            // backend's `acquire_shared`: the shared file lock is what
+            // protects the critical section from here on
+            unsafe { ReleaseSemaphore(self.sem, 1, ptr::null_mut()); }
+
+            result
+        } else if wait_status == WAIT_TIMEOUT {
+            self.recovered.set(true);
+
+            // blocking, same reasoning as the `acquire` fallback above
+            lock_file(file, 0)
+        } else {
+            Err(SemalockError::Os(io::Error::last_os_error()))
+        }
+    }
+
+    fn release(&self, file: &File, skip_fast_path: bool) -> Result<(), SemalockError> {
+        let unlock_code = unsafe { UnlockFile(file.as_raw_handle() as HANDLE, 0, 0, !0, !0) };
+
+        if unlock_code == 0 {
+            return Err(SemalockError::Os(io::Error::last_os_error()));
+        }
+
+        if skip_fast_path {
+            return Ok(());
+        }
+
+        let release_code = unsafe { ReleaseSemaphore(self.sem, 1, ptr::null_mut()) };
+
+        if release_code == 0 {
+            Err(SemalockError::Os(io::Error::last_os_error()))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn unlink(&self) -> Result<(), SemalockError> {
+        // unlike a POSIX named semaphore, a Windows kernel object is
+        // reference-counted and disappears on its own once every handle
+        // (including ones held by other processes) is closed; there's
+        // nothing for us to do.
+        Ok(())
+    }
+
+    fn recovered_last_acquire(&self) -> bool {
+        self.recovered.get()
+    }
+}
+
+fn lock_file(file: &File, flags: DWORD) -> Result<(), SemalockError> {
+    let handle = file.as_raw_handle() as HANDLE;
+
+    if handle == INVALID_HANDLE_VALUE {
+        return Err(SemalockError::Os(io::Error::last_os_error()));
+    }
+
+    let mut overlapped = overlapped();
+
+    let lock_code = unsafe { LockFileEx(handle, flags, 0, !0, !0, &mut overlapped) };
+
+    if lock_code == 0 {
+        Err(SemalockError::Os(io::Error::last_os_error()))
+    } else {
+        Ok(())
+    }
+}
+
+impl Drop for WindowsBackend {
+    fn drop(&mut self) {
+        unsafe { CloseHandle(self.sem); }
+    }
+}